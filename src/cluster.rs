@@ -0,0 +1,300 @@
+use crate::redislogic::{
+    get_redis_value, identify, open_connection, split_command, DatabaseInfo, KeyFilter,
+    RedisBackend, RedisValue, RedisViewerError, Timeouts, ViewerResult,
+};
+use redis::{
+    from_redis_value, Connection, ConnectionAddr, ConnectionInfo, ErrorKind, RedisError, Value,
+};
+use std::collections::{hash_map::Entry, HashMap};
+
+const SLOT_COUNT: u16 = 16384;
+
+pub(crate) type NodeAddress = (String, u16);
+
+pub(crate) type Node = Box<dyn RedisBackend + Send>;
+
+/// Opens a connection to the node at an address.
+pub(crate) type ConnectNode = Box<dyn FnMut(&NodeAddress) -> ViewerResult<Node> + Send>;
+
+struct SlotRange {
+    start: u16,
+    end: u16,
+    master: NodeAddress,
+}
+
+/// Connections to every master of a cluster, routed by hash slot. Nodes
+/// are discovered with `CLUSTER SLOTS` and the map is reloaded whenever a
+/// node answers with MOVED.
+pub struct ClusterConnection {
+    seed: NodeAddress,
+    connect_node: ConnectNode,
+    slots: Vec<SlotRange>,
+    nodes: HashMap<NodeAddress, Node>,
+}
+
+impl ClusterConnection {
+    pub fn connect(seed: ConnectionInfo, timeouts: Timeouts) -> ViewerResult<ClusterConnection> {
+        let seed_address = match &*seed.addr {
+            ConnectionAddr::Tcp(host, port) | ConnectionAddr::TcpTls { host, port, .. } => {
+                (host.clone(), *port)
+            }
+            ConnectionAddr::Unix(_) => {
+                return Err(RedisViewerError::Config(
+                    "a cluster needs a TCP address".into(),
+                ))
+            }
+        };
+        let connect = move |address: &NodeAddress| -> ViewerResult<Node> {
+            Ok(Box::new(connect_node(&seed, address, timeouts)?))
+        };
+        ClusterConnection::connect_with(seed_address, Box::new(connect))
+    }
+
+    /// Like `connect`, but reaches the nodes with `connect_node`, e.g. in
+    /// memory.
+    pub(crate) fn connect_with(
+        seed: NodeAddress,
+        connect_node: ConnectNode,
+    ) -> ViewerResult<ClusterConnection> {
+        let mut cluster = ClusterConnection {
+            seed,
+            connect_node,
+            slots: Vec::new(),
+            nodes: HashMap::new(),
+        };
+        cluster.refresh_slots()?;
+        Ok(cluster)
+    }
+
+    pub fn refresh_slots(&mut self) -> ViewerResult<()> {
+        let mut seed_node = (self.connect_node)(&self.seed)?;
+        let reply = seed_node.execute(&["CLUSTER", "SLOTS"])?;
+        let entries: Vec<Value> = from_redis_value(&reply)?;
+        let slots = entries
+            .iter()
+            .map(|entry| parse_slot_range(entry, &self.seed.0))
+            .collect::<redis::RedisResult<Vec<_>>>()?;
+        if slots.is_empty() {
+            let err =
+                RedisError::from((ErrorKind::ClusterDown, "the cluster has no slots assigned"));
+            return Err(err.into());
+        }
+
+        self.slots = slots;
+        let masters = self.masters();
+        self.nodes.retain(|address, _| masters.contains(address));
+        for master in masters {
+            self.node(master)?;
+        }
+        Ok(())
+    }
+
+    pub fn masters(&self) -> Vec<NodeAddress> {
+        let mut masters: Vec<NodeAddress> = self
+            .slots
+            .iter()
+            .map(|range| range.master.clone())
+            .collect();
+        masters.sort();
+        masters.dedup();
+        masters
+    }
+
+    fn node(&mut self, address: NodeAddress) -> ViewerResult<&mut dyn RedisBackend> {
+        let node = match self.nodes.entry(address) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let node = (self.connect_node)(entry.key())?;
+                entry.insert(node)
+            }
+        };
+        Ok(node.as_mut())
+    }
+
+    pub fn any_node(&mut self) -> ViewerResult<&mut dyn RedisBackend> {
+        let master = self.masters().into_iter().next().ok_or_else(|| {
+            RedisError::from((ErrorKind::ClusterDown, "the cluster has no masters"))
+        })?;
+        self.node(master)
+    }
+
+    fn master_for_slot(&self, slot: u16) -> redis::RedisResult<NodeAddress> {
+        self.slots
+            .iter()
+            .find(|range| range.start <= slot && slot <= range.end)
+            .map(|range| range.master.clone())
+            .ok_or_else(|| {
+                RedisError::from((
+                    ErrorKind::ClusterDown,
+                    "no node serves slot",
+                    slot.to_string(),
+                ))
+            })
+    }
+
+    /// Runs `run` on the node `route` picks. A node answering MOVED means
+    /// the slot map is out of date, so it is reloaded and `run` retried once
+    /// on the node `route` picks then.
+    fn with_moved_retry<T>(
+        &mut self,
+        route: impl Fn(&ClusterConnection) -> redis::RedisResult<NodeAddress>,
+        mut run: impl FnMut(&mut dyn RedisBackend) -> ViewerResult<T>,
+    ) -> ViewerResult<T> {
+        let address = route(self)?;
+        match run(self.node(address)?) {
+            Err(RedisViewerError::Redis(err)) if err.kind() == ErrorKind::Moved => {
+                self.refresh_slots()?;
+                let address = route(self)?;
+                run(self.node(address)?)
+            }
+            result => result,
+        }
+    }
+
+    /// Describes where a key lives, e.g. `slot 12539 on 10.0.0.3:7002`.
+    pub fn key_location(&self, key: &str) -> String {
+        let slot = key_slot(key.as_bytes());
+        match self.master_for_slot(slot) {
+            Ok((host, port)) => format!("slot {} on {}:{}", slot, host, port),
+            Err(_) => format!("slot {} (unassigned)", slot),
+        }
+    }
+
+    /// One `SCAN` step on one master, cursors are per node.
+    pub fn scan_keys(
+        &mut self,
+        master: &NodeAddress,
+        cursor: u64,
+        filter: &KeyFilter,
+    ) -> ViewerResult<(u64, Vec<String>)> {
+        self.with_moved_retry(
+            |_| Ok(master.clone()),
+            |node| node.scan_keys(cursor, filter),
+        )
+    }
+
+    pub fn get_redis_value(&mut self, key: &str) -> ViewerResult<RedisValue> {
+        let slot = key_slot(key.as_bytes());
+        self.with_moved_retry(
+            |cluster| cluster.master_for_slot(slot),
+            |node| get_redis_value(node, key),
+        )
+    }
+
+    /// Runs a command on the master owning its first argument as a key, or
+    /// on every master for commands without arguments such as FLUSHDB.
+    pub fn execute(&mut self, args: &[&str]) -> ViewerResult<Value> {
+        let (_, command_args) = split_command(args)?;
+        match command_args.first() {
+            Some(key) => {
+                let slot = key_slot(key.as_bytes());
+                self.with_moved_retry(
+                    |cluster| cluster.master_for_slot(slot),
+                    |node| node.execute(args),
+                )
+            }
+            None => {
+                let mut reply = Value::Nil;
+                for master in self.masters() {
+                    reply =
+                        self.with_moved_retry(|_| Ok(master.clone()), |node| node.execute(args))?;
+                }
+                Ok(reply)
+            }
+        }
+    }
+
+    /// A cluster only has db0, report it with the total across masters.
+    pub fn get_databases(&mut self) -> ViewerResult<Vec<DatabaseInfo>> {
+        let mut keys = 0;
+        for master in self.masters() {
+            let size: u64 = from_redis_value(&self.node(master)?.execute(&["DBSIZE"])?)?;
+            keys += size;
+        }
+        Ok(vec![DatabaseInfo { index: 0, keys }])
+    }
+
+    pub fn ping(&mut self) -> ViewerResult<()> {
+        for master in self.masters() {
+            self.node(master)?.ping()?;
+        }
+        Ok(())
+    }
+}
+
+fn connect_node(
+    seed: &ConnectionInfo,
+    (host, port): &NodeAddress,
+    timeouts: Timeouts,
+) -> redis::RedisResult<Connection> {
+    let addr = match &*seed.addr {
+        ConnectionAddr::TcpTls { insecure, .. } => ConnectionAddr::TcpTls {
+            host: host.clone(),
+            port: *port,
+            insecure: *insecure,
+        },
+        _ => ConnectionAddr::Tcp(host.clone(), *port),
+    };
+    let info = ConnectionInfo {
+        addr: Box::new(addr),
+        db: 0,
+        username: seed.username.clone(),
+        passwd: seed.passwd.clone(),
+    };
+    let mut connection = open_connection(info, timeouts)?;
+    identify(&mut connection)?;
+    Ok(connection)
+}
+
+/// Parses one `CLUSTER SLOTS` entry: `[start, end, [host, port, id], replicas...]`.
+fn parse_slot_range(entry: &Value, seed_host: &str) -> redis::RedisResult<SlotRange> {
+    let invalid = || RedisError::from((ErrorKind::TypeError, "unexpected CLUSTER SLOTS reply"));
+    let items = match entry {
+        Value::Bulk(items) if items.len() >= 3 => items,
+        _ => return Err(invalid()),
+    };
+    let node = match &items[2] {
+        Value::Bulk(node) if node.len() >= 2 => node,
+        _ => return Err(invalid()),
+    };
+    let host: String = from_redis_value(&node[0])?;
+    // an empty or unknown endpoint means "the host you asked"
+    let host = if host.is_empty() || host == "?" {
+        seed_host.to_string()
+    } else {
+        host
+    };
+    Ok(SlotRange {
+        start: from_redis_value(&items[0])?,
+        end: from_redis_value(&items[1])?,
+        master: (host, from_redis_value(&node[1])?),
+    })
+}
+
+/// The hash slot of a key, honouring `{hash tags}`.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let hashed = match key.iter().position(|byte| *byte == b'{') {
+        Some(open) => match key[open + 1..].iter().position(|byte| *byte == b'}') {
+            Some(length) if length > 0 => &key[open + 1..open + 1 + length],
+            _ => key,
+        },
+        None => key,
+    };
+    crc16(hashed) % SLOT_COUNT
+}
+
+/// CRC16-CCITT (XMODEM), as specified for redis cluster key hashing.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,473 @@
+//! An in-memory stand-in for a redis server, for tests and for trying the
+//! library without a server to talk to.
+
+use crate::cluster::{key_slot, ClusterConnection, Node, NodeAddress};
+use crate::redislogic::{
+    client_name, split_command, ConnectionConfig, DatabaseInfo, KeyFilter, RedisBackend,
+    RedisConnection, RedisValue, RedisViewerError, ServerInfo, ViewerResult,
+};
+use redis::{ErrorKind, RedisError, Value};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+const DATABASES: usize = 16;
+
+/// A connection to an in-memory server. Clones share the data the way two
+/// connections to one server would, but each has its own selected database.
+#[derive(Clone)]
+pub struct MemoryBackend {
+    server: Arc<Mutex<MemoryServer>>,
+    db: usize,
+}
+
+struct MemoryServer {
+    databases: Vec<BTreeMap<String, RedisValue>>,
+    offline: bool,
+    cluster: Option<ClusterNode>,
+}
+
+/// What a server set up with `set_cluster_slots` knows of its cluster.
+struct ClusterNode {
+    address: NodeAddress,
+    slots: Vec<(u16, u16, NodeAddress)>,
+}
+
+impl Default for MemoryBackend {
+    fn default() -> Self {
+        MemoryBackend::new()
+    }
+}
+
+impl MemoryBackend {
+    /// An empty server with 16 databases, connected to db0.
+    pub fn new() -> Self {
+        MemoryBackend {
+            server: Arc::new(Mutex::new(MemoryServer {
+                databases: vec![BTreeMap::new(); DATABASES],
+                offline: false,
+                cluster: None,
+            })),
+            db: 0,
+        }
+    }
+
+    /// Stores `value` under `key` in database `db`, replacing what was there.
+    pub fn insert(&self, db: usize, key: &str, value: RedisValue) {
+        if let Some(database) = self.server().databases.get_mut(db) {
+            database.insert(key.to_string(), value);
+        }
+    }
+
+    /// What database `db` holds under `key`.
+    pub fn get(&self, db: usize, key: &str) -> Option<RedisValue> {
+        self.server()
+            .databases
+            .get(db)
+            .and_then(|database| database.get(key).cloned())
+    }
+
+    /// While offline every command fails the way a dropped connection does,
+    /// and `connect` is refused.
+    pub fn set_offline(&self, offline: bool) {
+        self.server().offline = offline;
+    }
+
+    /// Opens a `RedisConnection` on the database `config` names, with its
+    /// read-only and production settings. Nothing else in `config` is used.
+    pub fn connect(&self, config: &ConnectionConfig) -> ViewerResult<RedisConnection> {
+        if self.server().offline {
+            return Err(offline(io::ErrorKind::ConnectionRefused));
+        }
+        let mut backend = MemoryBackend {
+            server: self.server.clone(),
+            db: 0,
+        };
+        backend.select_database(config.info.db)?;
+        let server_info = ServerInfo {
+            version: "memory".into(),
+            mode: "standalone".into(),
+            role: "master".into(),
+            protocol: 2,
+            client_name: client_name(),
+        };
+        Ok(RedisConnection::with_backend(backend, server_info, config))
+    }
+
+    /// Makes this server the cluster node at `address`. `slots` is what it
+    /// answers to `CLUSTER SLOTS`: ranges of hash slots with the master
+    /// serving them. Keys in a slot of another master are answered with
+    /// MOVED, as a real node does.
+    pub fn set_cluster_slots(&self, address: (&str, u16), slots: &[(u16, u16, (&str, u16))]) {
+        let owned = |(host, port): (&str, u16)| (host.to_string(), port);
+        self.server().cluster = Some(ClusterNode {
+            address: owned(address),
+            slots: slots
+                .iter()
+                .map(|&(start, end, master)| (start, end, owned(master)))
+                .collect(),
+        });
+    }
+
+    /// Opens a cluster `RedisConnection` seeded by this server, which finds
+    /// the other masters among `nodes`. Every server needs its
+    /// `set_cluster_slots` first; only db0 exists on a cluster.
+    pub fn connect_cluster(
+        &self,
+        config: &ConnectionConfig,
+        nodes: &[MemoryBackend],
+    ) -> ViewerResult<RedisConnection> {
+        let seed = self.cluster_address()?;
+        let mut nodes = nodes.to_vec();
+        nodes.push(self.clone());
+        let connect = move |address: &NodeAddress| -> ViewerResult<Node> {
+            for node in &nodes {
+                if node.cluster_address().ok().as_ref() == Some(address) {
+                    if node.server().offline {
+                        return Err(offline(io::ErrorKind::ConnectionRefused));
+                    }
+                    return Ok(Box::new(MemoryBackend {
+                        server: node.server.clone(),
+                        db: 0,
+                    }));
+                }
+            }
+            Err(offline(io::ErrorKind::ConnectionRefused))
+        };
+        let cluster = ClusterConnection::connect_with(seed, Box::new(connect))?;
+        let server_info = ServerInfo {
+            version: "memory".into(),
+            mode: "cluster".into(),
+            role: "master".into(),
+            protocol: 2,
+            client_name: client_name(),
+        };
+        Ok(RedisConnection::with_cluster(cluster, server_info, config))
+    }
+
+    fn cluster_address(&self) -> ViewerResult<NodeAddress> {
+        match &self.server().cluster {
+            Some(node) => Ok(node.address.clone()),
+            None => Err(RedisViewerError::Config(
+                "the in-memory server is not a cluster node".into(),
+            )),
+        }
+    }
+
+    /// Answers MOVED for a key whose slot another master serves.
+    fn check_slot(&self, key: &str) -> ViewerResult<()> {
+        let server = self.server();
+        let node = match &server.cluster {
+            Some(node) => node,
+            None => return Ok(()),
+        };
+        let slot = key_slot(key.as_bytes());
+        let master = node
+            .slots
+            .iter()
+            .find(|(start, end, _)| *start <= slot && slot <= *end)
+            .map(|(_, _, master)| master);
+        match master {
+            Some((host, port)) if (host, port) != (&node.address.0, &node.address.1) => {
+                let moved = format!("{} {}:{}", slot, host, port);
+                Err(RedisError::from((ErrorKind::Moved, "MOVED", moved)).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The `CLUSTER SLOTS` reply: `[start, end, [host, port, id]]` per range.
+    fn cluster_slots(&self) -> ViewerResult<Value> {
+        let server = self.online()?;
+        let node = server.cluster.as_ref().ok_or_else(|| {
+            RedisError::from((
+                ErrorKind::ResponseError,
+                "This instance has cluster support disabled",
+            ))
+        })?;
+        let ranges = node
+            .slots
+            .iter()
+            .map(|(start, end, (host, port))| {
+                let master = vec![
+                    Value::Data(host.clone().into_bytes()),
+                    Value::Int(*port as i64),
+                    Value::Data(format!("{}:{}", host, port).into_bytes()),
+                ];
+                Value::Bulk(vec![
+                    Value::Int(*start as i64),
+                    Value::Int(*end as i64),
+                    Value::Bulk(master),
+                ])
+            })
+            .collect();
+        Ok(Value::Bulk(ranges))
+    }
+
+    fn server(&self) -> MutexGuard<'_, MemoryServer> {
+        // a test that panicked while holding the lock leaves usable data behind
+        self.server.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The server's data, unless it is offline.
+    fn online(&self) -> ViewerResult<MutexGuard<'_, MemoryServer>> {
+        let server = self.server();
+        if server.offline {
+            return Err(offline(io::ErrorKind::ConnectionReset));
+        }
+        Ok(server)
+    }
+
+    /// Runs `f` on the selected database, unless the server is offline.
+    fn with_database<T>(
+        &self,
+        f: impl FnOnce(&mut BTreeMap<String, RedisValue>) -> ViewerResult<T>,
+    ) -> ViewerResult<T> {
+        f(&mut self.online()?.databases[self.db])
+    }
+}
+
+fn offline(kind: io::ErrorKind) -> RedisViewerError {
+    RedisError::from(io::Error::new(kind, "the in-memory server is offline")).into()
+}
+
+/// What `TYPE` calls a value.
+fn type_name(value: &RedisValue) -> &'static str {
+    match value {
+        RedisValue::String(_) => "string",
+        RedisValue::List(_) => "list",
+        RedisValue::Set(_) => "set",
+        RedisValue::ZSet(_) => "zset",
+        RedisValue::Hash(_) => "hash",
+        RedisValue::Null => "none",
+    }
+}
+
+/// Matches the glob patterns of `SCAN MATCH` and `KEYS`: `*`, `?`, `[abc]`,
+/// `[^a]`, `[a-z]` and `\` escapes.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => (0..=key.len()).any(|skip| glob_match(rest, &key[skip..])),
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((b'[', rest)) => match key.split_first() {
+            Some((byte, key_rest)) => match match_class(rest, *byte) {
+                Some((true, pattern_rest)) => glob_match(pattern_rest, key_rest),
+                _ => false,
+            },
+            None => false,
+        },
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            key.first() == Some(&rest[0]) && glob_match(&rest[1..], &key[1..])
+        }
+        Some((byte, rest)) => key.first() == Some(byte) && glob_match(rest, &key[1..]),
+    }
+}
+
+/// Matches `byte` against the class after a `[`, returning whether it
+/// matched and the pattern after the closing `]`, or `None` if the class
+/// is never closed.
+fn match_class(mut class: &[u8], byte: u8) -> Option<(bool, &[u8])> {
+    let negated = class.first() == Some(&b'^');
+    if negated {
+        class = &class[1..];
+    }
+    let mut matched = false;
+    loop {
+        match class {
+            [] => return None,
+            [b']', rest @ ..] => return Some((matched != negated, rest)),
+            [b'\\', escaped, rest @ ..] => {
+                matched |= *escaped == byte;
+                class = rest;
+            }
+            [start, b'-', end, rest @ ..] if *end != b']' => {
+                let (low, high) = if start <= end {
+                    (*start, *end)
+                } else {
+                    (*end, *start)
+                };
+                matched |= low <= byte && byte <= high;
+                class = rest;
+            }
+            [single, rest @ ..] => {
+                matched |= *single == byte;
+                class = rest;
+            }
+        }
+    }
+}
+
+fn wrong_type() -> RedisViewerError {
+    RedisError::from((
+        ErrorKind::ResponseError,
+        "WRONGTYPE Operation against a key holding the wrong kind of value",
+    ))
+    .into()
+}
+
+impl RedisBackend for MemoryBackend {
+    fn scan_keys(&mut self, cursor: u64, filter: &KeyFilter) -> ViewerResult<(u64, Vec<String>)> {
+        self.with_database(|database| {
+            // like redis, COUNT is how many keys are looked at, not returned
+            let start = cursor as usize;
+            let keys: Vec<String> = database
+                .iter()
+                .skip(start)
+                .take(filter.count)
+                .filter(|(key, value)| {
+                    let type_matches = match &filter.key_type {
+                        Some(key_type) => key_type.eq_ignore_ascii_case(type_name(value)),
+                        None => true,
+                    };
+                    type_matches && glob_match(filter.pattern.as_bytes(), key.as_bytes())
+                })
+                .map(|(key, _)| key.clone())
+                .collect();
+            let next = start + filter.count;
+            let next_cursor = if next >= database.len() {
+                0
+            } else {
+                next as u64
+            };
+            Ok((next_cursor, keys))
+        })
+    }
+
+    fn key_type(&mut self, key: &str) -> ViewerResult<String> {
+        self.check_slot(key)?;
+        self.with_database(|database| Ok(database.get(key).map_or("none", type_name).to_string()))
+    }
+
+    fn get_string(&mut self, key: &str) -> ViewerResult<String> {
+        self.with_database(|database| match database.get(key) {
+            Some(RedisValue::String(value)) => Ok(value.clone()),
+            _ => Err(wrong_type()),
+        })
+    }
+
+    fn get_list(&mut self, key: &str) -> ViewerResult<Vec<String>> {
+        self.with_database(|database| match database.get(key) {
+            Some(RedisValue::List(values)) => Ok(values.clone()),
+            None => Ok(Vec::new()),
+            _ => Err(wrong_type()),
+        })
+    }
+
+    fn get_set(&mut self, key: &str) -> ViewerResult<Vec<String>> {
+        self.with_database(|database| match database.get(key) {
+            Some(RedisValue::Set(members)) => Ok(members.clone()),
+            None => Ok(Vec::new()),
+            _ => Err(wrong_type()),
+        })
+    }
+
+    fn get_sorted_set(&mut self, key: &str) -> ViewerResult<Vec<(String, String)>> {
+        self.with_database(|database| match database.get(key) {
+            Some(RedisValue::ZSet(members)) => Ok(members.clone()),
+            None => Ok(Vec::new()),
+            _ => Err(wrong_type()),
+        })
+    }
+
+    fn get_hash(&mut self, key: &str) -> ViewerResult<HashMap<String, String>> {
+        self.with_database(|database| match database.get(key) {
+            Some(RedisValue::Hash(fields)) => Ok(fields.clone()),
+            None => Ok(HashMap::new()),
+            _ => Err(wrong_type()),
+        })
+    }
+
+    fn select_database(&mut self, db: i64) -> ViewerResult<()> {
+        self.ping()?;
+        if db < 0 || db as usize >= DATABASES {
+            return Err(
+                RedisError::from((ErrorKind::ResponseError, "DB index is out of range")).into(),
+            );
+        }
+        self.db = db as usize;
+        Ok(())
+    }
+
+    fn get_databases(&mut self) -> ViewerResult<Vec<DatabaseInfo>> {
+        Ok(self
+            .online()?
+            .databases
+            .iter()
+            .enumerate()
+            .map(|(index, database)| DatabaseInfo {
+                index: index as i64,
+                keys: database.len() as u64,
+            })
+            .collect())
+    }
+
+    fn info(&mut self, section: &str) -> ViewerResult<String> {
+        let server = self.online()?;
+        let mut info = format!("# {}\r\n", section);
+        if section == "keyspace" {
+            for (index, database) in server.databases.iter().enumerate() {
+                if !database.is_empty() {
+                    info += &format!(
+                        "db{}:keys={},expires=0,avg_ttl=0\r\n",
+                        index,
+                        database.len()
+                    );
+                }
+            }
+        }
+        Ok(info)
+    }
+
+    fn ping(&mut self) -> ViewerResult<()> {
+        self.online().map(|_| ())
+    }
+
+    /// Understands the commands `WriteAction`s send: SET, DEL, RENAME and
+    /// FLUSHDB, and `CLUSTER SLOTS`.
+    fn execute(&mut self, args: &[&str]) -> ViewerResult<Value> {
+        let (name, args) = split_command(args)?;
+        let name = name.to_uppercase();
+        if name == "CLUSTER" {
+            return match args {
+                [subcommand] if subcommand.eq_ignore_ascii_case("SLOTS") => self.cluster_slots(),
+                _ => Err(RedisViewerError::Refused(
+                    "only CLUSTER SLOTS is supported by the in-memory backend".into(),
+                )),
+            };
+        }
+        if let Some(key) = args.first() {
+            self.check_slot(key)?;
+        }
+        self.with_database(|database| match (name.as_str(), args) {
+            ("SET", [key, value]) => {
+                database.insert(key.to_string(), RedisValue::String(value.to_string()));
+                Ok(Value::Okay)
+            }
+            ("DEL", keys) if !keys.is_empty() => {
+                let removed = keys
+                    .iter()
+                    .filter(|key| database.remove(**key).is_some())
+                    .count();
+                Ok(Value::Int(removed as i64))
+            }
+            ("RENAME", [key, new_key]) => {
+                let value = database
+                    .remove(*key)
+                    .ok_or_else(|| RedisError::from((ErrorKind::ResponseError, "no such key")))?;
+                database.insert(new_key.to_string(), value);
+                Ok(Value::Okay)
+            }
+            ("FLUSHDB", []) => {
+                database.clear();
+                Ok(Value::Okay)
+            }
+            _ => Err(RedisViewerError::Refused(format!(
+                "{} is not supported by the in-memory backend",
+                name
+            ))),
+        })
+    }
+}
@@ -0,0 +1,289 @@
+use crate::redislogic::{
+    build_connection_info, build_key_limit, build_sentinel_config, build_socket_connection_info,
+    build_timeouts, parse_connection_url, ConnectionConfig, DEFAULT_KEY_LIMIT,
+    DEFAULT_NAMESPACE_DELIMITERS,
+};
+use crate::tunnel::{SshOptions, TlsOptions};
+use druid::{Color, Data, Lens};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::PathBuf};
+
+#[derive(Clone, Copy, Data, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionMode {
+    Tcp,
+    Socket,
+    Url,
+    Sentinel,
+    Cluster,
+}
+
+/// Everything the connection form edits. Passwords are never written to
+/// the profiles file and have to be entered again on connect.
+#[derive(Clone, Data, Lens, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConnectionProfile {
+    pub name: String,
+    pub color: String,
+    pub mode: ConnectionMode,
+    pub address: String,
+    pub port: String,
+    pub socket_path: String,
+    pub url: String,
+    pub sentinels: String,
+    pub sentinel_master: String,
+    pub sentinel_use_replica: bool,
+    pub db: String,
+    pub username: String,
+    #[serde(skip)]
+    pub password: String,
+    pub tls: bool,
+    pub tls_ca_path: String,
+    pub tls_cert_path: String,
+    pub tls_key_path: String,
+    pub tls_skip_hostname_check: bool,
+    pub tls_accept_invalid_certs: bool,
+    pub ssh: bool,
+    pub ssh_host: String,
+    pub ssh_port: String,
+    pub ssh_user: String,
+    pub ssh_key_path: String,
+    pub ssh_use_agent: bool,
+    #[serde(skip)]
+    pub ssh_passphrase: String,
+    pub connect_timeout: String,
+    pub read_timeout: String,
+    pub write_timeout: String,
+    pub read_only: bool,
+    pub production: bool,
+    pub namespace_delimiters: String,
+    pub key_limit: String,
+}
+
+impl Default for ConnectionProfile {
+    fn default() -> Self {
+        ConnectionProfile {
+            name: "localhost".into(),
+            color: "#1a991a".into(),
+            mode: ConnectionMode::Tcp,
+            address: "127.0.0.1".into(),
+            port: "6379".into(),
+            socket_path: "/tmp/redis.sock".into(),
+            url: "redis://127.0.0.1:6379/0".into(),
+            sentinels: "127.0.0.1:26379".into(),
+            sentinel_master: "mymaster".into(),
+            sentinel_use_replica: false,
+            db: "0".into(),
+            username: String::new(),
+            password: String::new(),
+            tls: false,
+            tls_ca_path: String::new(),
+            tls_cert_path: String::new(),
+            tls_key_path: String::new(),
+            tls_skip_hostname_check: false,
+            tls_accept_invalid_certs: false,
+            ssh: false,
+            ssh_host: String::new(),
+            ssh_port: "22".into(),
+            ssh_user: String::new(),
+            ssh_key_path: String::new(),
+            ssh_use_agent: true,
+            ssh_passphrase: String::new(),
+            connect_timeout: "5".into(),
+            read_timeout: "30".into(),
+            write_timeout: "30".into(),
+            read_only: false,
+            production: false,
+            namespace_delimiters: DEFAULT_NAMESPACE_DELIMITERS.into(),
+            key_limit: DEFAULT_KEY_LIMIT.to_string(),
+        }
+    }
+}
+
+impl ConnectionProfile {
+    pub fn connection_config(&self) -> Result<ConnectionConfig, String> {
+        let timeouts = build_timeouts(
+            &self.connect_timeout,
+            &self.read_timeout,
+            &self.write_timeout,
+        )?;
+        let mut config = self.mode_config()?.with_timeouts(timeouts);
+        if self.read_only {
+            config = config.in_read_only_mode();
+        }
+        if self.production {
+            config = config.in_production_mode();
+        }
+        Ok(config
+            .with_namespace_delimiters(&self.namespace_delimiters)
+            .with_key_limit(build_key_limit(&self.key_limit)?))
+    }
+
+    fn mode_config(&self) -> Result<ConnectionConfig, String> {
+        match self.mode {
+            ConnectionMode::Tcp => {
+                let mut config = ConnectionConfig::new(build_connection_info(
+                    &self.address,
+                    &self.port,
+                    &self.db,
+                    &self.username,
+                    &self.password,
+                )?);
+                if self.tls {
+                    config = config.with_tls(self.tls_options());
+                }
+                if self.ssh {
+                    config = config.with_ssh(self.ssh_options()?);
+                }
+                Ok(config)
+            }
+            ConnectionMode::Socket => build_socket_connection_info(
+                &self.socket_path,
+                &self.db,
+                &self.username,
+                &self.password,
+            )
+            .map(ConnectionConfig::new),
+            ConnectionMode::Url => {
+                let mut info = parse_connection_url(&self.url)?;
+                if !self.password.is_empty() {
+                    info.passwd = Some(self.password.clone());
+                }
+                Ok(ConnectionConfig::new(info))
+            }
+            ConnectionMode::Cluster => {
+                let config = ConnectionConfig::new(build_connection_info(
+                    &self.address,
+                    &self.port,
+                    "0",
+                    &self.username,
+                    &self.password,
+                )?)
+                .in_cluster_mode();
+                // the cluster form only has the TLS checkbox, the certificate
+                // fields filled in for another mode don't apply
+                if self.tls {
+                    Ok(config.with_tls(TlsOptions::default()))
+                } else {
+                    Ok(config)
+                }
+            }
+            ConnectionMode::Sentinel => build_sentinel_config(
+                &self.sentinels,
+                &self.sentinel_master,
+                &self.db,
+                &self.username,
+                &self.password,
+                self.sentinel_use_replica,
+            ),
+        }
+    }
+
+    pub fn label_color(&self) -> Color {
+        Color::from_hex_str(self.color.trim()).unwrap_or(Color::grey8(0x80))
+    }
+
+    /// The copy that gets written to disk, with the password stripped from
+    /// the URL as well as from the password field.
+    fn without_secrets(&self) -> ConnectionProfile {
+        let mut profile = self.clone();
+        profile.password = String::new();
+        profile.ssh_passphrase = String::new();
+        if let Some(mut url) = redis::parse_redis_url(&profile.url) {
+            let _ = url.set_password(None);
+            let query: Vec<(String, String)> = url
+                .query_pairs()
+                .filter(|(name, _)| name != "pass")
+                .map(|(name, value)| (name.into_owned(), value.into_owned()))
+                .collect();
+            if query.is_empty() {
+                url.set_query(None);
+            } else {
+                url.query_pairs_mut().clear().extend_pairs(query);
+            }
+            profile.url = url.to_string();
+        }
+        profile
+    }
+
+    fn tls_options(&self) -> TlsOptions {
+        TlsOptions {
+            ca_bundle: optional_path(&self.tls_ca_path),
+            client_cert: optional_path(&self.tls_cert_path),
+            client_key: optional_path(&self.tls_key_path),
+            skip_hostname_check: self.tls_skip_hostname_check,
+            accept_invalid_certs: self.tls_accept_invalid_certs,
+        }
+    }
+
+    fn ssh_options(&self) -> Result<SshOptions, String> {
+        let host = self.ssh_host.trim();
+        if host.is_empty() {
+            return Err("SSH host is required".into());
+        }
+        let port = self.ssh_port.trim().parse().map_err(|_| {
+            format!(
+                "SSH port must be a number from 0 to 65535, got `{}`",
+                self.ssh_port
+            )
+        })?;
+        Ok(SshOptions {
+            host: host.to_string(),
+            port,
+            user: self.ssh_user.trim().to_string(),
+            key_file: optional_path(&self.ssh_key_path),
+            use_agent: self.ssh_use_agent,
+            passphrase: Some(self.ssh_passphrase.clone()).filter(|phrase| !phrase.is_empty()),
+        })
+    }
+}
+
+fn optional_path(path: &str) -> Option<PathBuf> {
+    let path = path.trim();
+    if path.is_empty() {
+        None
+    } else {
+        Some(PathBuf::from(path))
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct ProfilesFile {
+    #[serde(default)]
+    profiles: Vec<ConnectionProfile>,
+}
+
+pub fn profiles_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("druid_redis_viewer").join("profiles.toml"))
+}
+
+pub fn load_profiles() -> Result<Vec<ConnectionProfile>, String> {
+    let path = match profiles_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(Vec::new()),
+    };
+    let contents = fs::read_to_string(&path)
+        .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+    let file: ProfilesFile = toml::from_str(&contents)
+        .map_err(|err| format!("failed to parse {}: {}", path.display(), err))?;
+    Ok(file.profiles)
+}
+
+pub fn save_profiles<'a>(
+    profiles: impl IntoIterator<Item = &'a ConnectionProfile>,
+) -> Result<(), String> {
+    let path = profiles_path().ok_or("no config directory on this platform")?;
+    let file = ProfilesFile {
+        profiles: profiles
+            .into_iter()
+            .map(ConnectionProfile::without_secrets)
+            .collect(),
+    };
+    let contents = toml::to_string_pretty(&file)
+        .map_err(|err| format!("failed to serialize profiles: {}", err))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|err| format!("failed to create {}: {}", dir.display(), err))?;
+    }
+    fs::write(&path, contents).map_err(|err| format!("failed to write {}: {}", path.display(), err))
+}
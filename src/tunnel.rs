@@ -0,0 +1,594 @@
+use native_tls::{Certificate, Identity, TlsConnector, TlsStream};
+use std::{
+    collections::VecDeque,
+    env, fs,
+    io::{self, BufRead, BufReader, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::{
+    net::Shutdown,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, OpenOptionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    process,
+    sync::atomic::AtomicUsize,
+};
+
+/// How often the tunnel checks for the redis client while it connects.
+#[cfg(unix)]
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+const TUNNEL_SOCKET_NAME: &str = "tls.sock";
+const SSH_STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+/// How much of what ssh printed last is kept for the error message.
+const SSH_STDERR_LINES: usize = 20;
+
+/// How to verify the server and identify to it over TLS.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// PEM certificates to trust instead of the system roots.
+    pub ca_bundle: Option<PathBuf>,
+    /// A PEM client certificate, for servers that require mutual TLS.
+    pub client_cert: Option<PathBuf>,
+    /// The PKCS#8 PEM key of `client_cert`.
+    pub client_key: Option<PathBuf>,
+    /// Accepts a certificate issued for another host name.
+    pub skip_hostname_check: bool,
+    /// Accepts any certificate, whoever issued it and whenever it expired.
+    pub accept_invalid_certs: bool,
+}
+
+impl TlsOptions {
+    /// Whether the redis client's own insecure mode, which skips both
+    /// checks, is what was asked for.
+    pub fn is_insecure(&self) -> bool {
+        self.skip_hostname_check && self.accept_invalid_certs
+    }
+
+    /// The redis client can only do default-verified or fully insecure TLS,
+    /// anything beyond that has to go through a `TlsTunnel`.
+    pub fn needs_custom_connector(&self) -> bool {
+        self.ca_bundle.is_some()
+            || self.client_cert.is_some()
+            || self.client_key.is_some()
+            || self.skip_hostname_check != self.accept_invalid_certs
+    }
+}
+
+/// Terminates TLS locally and exposes the server as a unix socket, for
+/// CA bundles, client certificates and hostname-only checks. The socket
+/// sits in a directory only the current user can enter and accepts a
+/// single connection, each reconnect opens a new tunnel.
+///
+/// The tunnel relays one request and its replies at a time, which is how
+/// the viewer talks to redis; `SUBSCRIBE` and `MONITOR` are not supported.
+pub struct TlsTunnel {
+    socket_dir: PrivateDir,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl TlsTunnel {
+    /// Connects to `host:port` and verifies the certificate against `domain`,
+    /// which differs from `host` when the server is reached over SSH.
+    #[cfg(unix)]
+    pub fn open(
+        host: &str,
+        port: u16,
+        domain: &str,
+        options: &TlsOptions,
+        connect_timeout: Duration,
+    ) -> Result<TlsTunnel, String> {
+        let connector = build_connector(options)?;
+        // handshake once up front so certificate problems are reported here
+        // rather than as a reset connection on the redis side
+        let first_stream = connect_tls(&connector, host, port, domain, connect_timeout)?;
+
+        let socket_dir = PrivateDir::create()
+            .map_err(|err| format!("failed to open local TLS tunnel: {}", err))?;
+        let socket_path = socket_dir.path.join(TUNNEL_SOCKET_NAME);
+        let listener = UnixListener::bind(&socket_path)
+            .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+            .map_err(|err| format!("failed to open local TLS tunnel: {}", err))?;
+
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let accept_shutdown = shutdown.clone();
+        thread::spawn(move || {
+            // the handshake is done by now, so only the redis client's own
+            // connection gets the stream and the socket goes away after it
+            let deadline = Instant::now() + connect_timeout;
+            while !accept_shutdown.load(Ordering::Relaxed) && Instant::now() < deadline {
+                match listener.accept() {
+                    Ok((local, _)) => {
+                        drop(listener);
+                        let _ = fs::remove_file(&socket_path);
+                        // accepted sockets inherit non-blocking mode on some platforms
+                        if local.set_nonblocking(false).is_ok() {
+                            relay(local, first_stream);
+                        }
+                        return;
+                    }
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                    Err(_) => return,
+                }
+            }
+        });
+
+        Ok(TlsTunnel {
+            socket_dir,
+            shutdown,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn open(
+        _host: &str,
+        _port: u16,
+        _domain: &str,
+        _options: &TlsOptions,
+        _connect_timeout: Duration,
+    ) -> Result<TlsTunnel, String> {
+        Err(
+            "CA bundles, client certificates, SSH and skipping only one check \
+             need unix sockets, which this platform does not have"
+                .into(),
+        )
+    }
+
+    /// Where the redis client connects to.
+    pub fn socket_path(&self) -> PathBuf {
+        self.socket_dir.path.join(TUNNEL_SOCKET_NAME)
+    }
+}
+
+impl Drop for TlsTunnel {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A directory under the system temp dir that only this user can enter,
+/// removed with everything in it when dropped.
+struct PrivateDir {
+    path: PathBuf,
+}
+
+impl PrivateDir {
+    #[cfg(unix)]
+    fn create() -> io::Result<PrivateDir> {
+        static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+        let name = format!(
+            "druid-redis-viewer-{}-{}",
+            process::id(),
+            NEXT_DIR.fetch_add(1, Ordering::Relaxed)
+        );
+        let path = env::temp_dir().join(name);
+        // `create` rather than `create_all`: an existing directory, possibly
+        // someone else's, is an error instead of being reused
+        fs::DirBuilder::new().mode(0o700).create(&path)?;
+        Ok(PrivateDir { path })
+    }
+}
+
+impl Drop for PrivateDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+fn build_connector(options: &TlsOptions) -> Result<TlsConnector, String> {
+    let mut builder = TlsConnector::builder();
+    if let Some(path) = &options.ca_bundle {
+        for certificate in read_pem_certificates(path)? {
+            builder.add_root_certificate(certificate);
+        }
+    }
+    match (&options.client_cert, &options.client_key) {
+        (Some(cert_path), Some(key_path)) => {
+            let identity = Identity::from_pkcs8(&read_file(cert_path)?, &read_file(key_path)?)
+                .map_err(|err| {
+                    format!(
+                        "invalid client certificate or key (the key must be PKCS#8 PEM): {}",
+                        err
+                    )
+                })?;
+            builder.identity(identity);
+        }
+        (None, None) => {}
+        _ => return Err("client certificate and client key must be given together".into()),
+    }
+    builder.danger_accept_invalid_certs(options.accept_invalid_certs);
+    builder.danger_accept_invalid_hostnames(options.skip_hostname_check);
+    builder
+        .build()
+        .map_err(|err| format!("failed to set up TLS: {}", err))
+}
+
+fn read_pem_certificates(path: &Path) -> Result<Vec<Certificate>, String> {
+    let pem = String::from_utf8(read_file(path)?)
+        .map_err(|_| format!("{} is not a PEM file", path.display()))?;
+    let end_marker = "-----END CERTIFICATE-----";
+    let certificates = pem
+        .split_inclusive(end_marker)
+        .filter(|block| block.contains("-----BEGIN CERTIFICATE-----"))
+        .map(|block| Certificate::from_pem(block.trim().as_bytes()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("invalid certificate in {}: {}", path.display(), err))?;
+    if certificates.is_empty() {
+        return Err(format!("no certificates found in {}", path.display()));
+    }
+    Ok(certificates)
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|err| format!("failed to read {}: {}", path.display(), err))
+}
+
+fn connect_tls(
+    connector: &TlsConnector,
+    host: &str,
+    port: u16,
+    domain: &str,
+    connect_timeout: Duration,
+) -> Result<TlsStream<TcpStream>, String> {
+    let stream = connect_with_timeout(host, port, connect_timeout)
+        .map_err(|err| format!("failed to connect to {}:{}: {}", host, port, err))?;
+    // bound the handshake as well, the relay lifts the timeout afterwards
+    stream
+        .set_read_timeout(Some(connect_timeout))
+        .map_err(|err| format!("failed to connect to {}:{}: {}", host, port, err))?;
+    connector
+        .connect(domain, stream)
+        .map_err(|err| format!("TLS handshake with {} failed: {}", domain, err))
+}
+
+fn connect_with_timeout(host: &str, port: u16, timeout: Duration) -> std::io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| ErrorKind::NotFound.into()))
+}
+
+/// Relays between the redis client and the server until either side
+/// closes. The TLS stream can't be read and written from two threads, so
+/// this blocks on the client until it has sent whole commands, then on the
+/// server until every one of them has been answered.
+#[cfg(unix)]
+fn relay(mut local: UnixStream, mut upstream: TlsStream<TcpStream>) {
+    // the redis client applies its own read timeout to the socket
+    if upstream.get_ref().set_read_timeout(None).is_ok() {
+        let _ = relay_commands(&mut local, &mut upstream);
+    }
+    let _ = upstream.shutdown();
+    let _ = local.shutdown(Shutdown::Both);
+}
+
+#[cfg(unix)]
+fn relay_commands(local: &mut UnixStream, upstream: &mut TlsStream<TcpStream>) -> io::Result<()> {
+    let mut commands = RespFramer::default();
+    let mut replies = RespFramer::default();
+    let mut unanswered = 0;
+    let mut buffer = vec![0u8; 16 * 1024];
+    loop {
+        if unanswered == 0 {
+            let read = local.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(());
+            }
+            upstream.write_all(&buffer[..read])?;
+            unanswered += commands.feed(&buffer[..read])?;
+        } else {
+            let read = upstream.read(&mut buffer)?;
+            if read == 0 {
+                return Ok(());
+            }
+            local.write_all(&buffer[..read])?;
+            unanswered -= replies.feed(&buffer[..read])?.min(unanswered);
+        }
+    }
+}
+
+/// Counts the RESP2 values in a stream that arrives in arbitrary pieces,
+/// without keeping more of it than one header line.
+#[cfg(unix)]
+#[derive(Default)]
+struct RespFramer {
+    /// The start of a header line whose end hasn't arrived yet.
+    line: Vec<u8>,
+    /// Bytes of a bulk string, and its closing CRLF, still to come.
+    skip: usize,
+    /// Values still missing from the one being read, 0 between values.
+    pending: usize,
+}
+
+#[cfg(unix)]
+impl RespFramer {
+    /// Takes the next piece of the stream and returns how many values it
+    /// completed.
+    fn feed(&mut self, mut bytes: &[u8]) -> io::Result<usize> {
+        let mut completed = 0;
+        while !bytes.is_empty() {
+            if self.skip > 0 {
+                let skipped = self.skip.min(bytes.len());
+                self.skip -= skipped;
+                bytes = &bytes[skipped..];
+                if self.skip == 0 {
+                    completed += self.finish_value();
+                }
+                continue;
+            }
+            match bytes.iter().position(|byte| *byte == b'\n') {
+                Some(end) => {
+                    self.line.extend_from_slice(&bytes[..=end]);
+                    bytes = &bytes[end + 1..];
+                    let line = std::mem::take(&mut self.line);
+                    completed += self.read_header(&line)?;
+                }
+                None => {
+                    self.line.extend_from_slice(bytes);
+                    bytes = &[];
+                }
+            }
+        }
+        Ok(completed)
+    }
+
+    fn read_header(&mut self, line: &[u8]) -> io::Result<usize> {
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "not a RESP2 stream");
+        let (kind, rest) = line.split_first().ok_or_else(invalid)?;
+        let number = || -> io::Result<i64> {
+            std::str::from_utf8(rest)
+                .ok()
+                .and_then(|rest| rest.trim_end().parse().ok())
+                .ok_or_else(invalid)
+        };
+        if self.pending == 0 {
+            self.pending = 1;
+        }
+        match kind {
+            b'+' | b'-' | b':' => Ok(self.finish_value()),
+            b'$' => match number()? {
+                length if length < 0 => Ok(self.finish_value()),
+                length => {
+                    self.skip = length as usize + 2;
+                    Ok(0)
+                }
+            },
+            b'*' => match number()? {
+                count if count <= 0 => Ok(self.finish_value()),
+                // the array stands for its elements from now on
+                count => {
+                    self.pending += count as usize - 1;
+                    Ok(0)
+                }
+            },
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Marks one value done, returning 1 if that completed the outermost one.
+    fn finish_value(&mut self) -> usize {
+        self.pending -= 1;
+        usize::from(self.pending == 0)
+    }
+}
+
+/// The bastion host an `SshTunnel` goes through.
+#[derive(Clone, Debug)]
+pub struct SshOptions {
+    /// The bastion host name or address.
+    pub host: String,
+    /// Its ssh port, usually 22.
+    pub port: u16,
+    /// Who to log in as, `ssh`'s default when empty.
+    pub user: String,
+    /// A private key to log in with, passed as `ssh -i`.
+    pub key_file: Option<PathBuf>,
+    /// Whether `ssh` may ask a running ssh-agent for keys.
+    pub use_agent: bool,
+    /// The passphrase of `key_file`. `ssh` gets it from a throwaway askpass
+    /// script, which needs a unix system and OpenSSH 8.4 or newer; elsewhere
+    /// load the key into ssh-agent instead.
+    pub passphrase: Option<String>,
+}
+
+/// A `ssh -N -L` child process forwarding a loopback port through a
+/// bastion host. The process is killed when the tunnel is dropped.
+pub struct SshTunnel {
+    child: Child,
+    local_port: u16,
+}
+
+impl SshTunnel {
+    pub fn open(
+        options: &SshOptions,
+        target_host: &str,
+        target_port: u16,
+    ) -> Result<SshTunnel, String> {
+        let local_port = free_local_port()?;
+
+        let mut command = Command::new("ssh");
+        command
+            .arg("-N")
+            .arg("-L")
+            .arg(format!(
+                "127.0.0.1:{}:{}:{}",
+                local_port, target_host, target_port
+            ))
+            .arg("-p")
+            .arg(options.port.to_string())
+            .arg("-o")
+            .arg("ExitOnForwardFailure=yes")
+            .arg("-o")
+            .arg("ServerAliveInterval=15")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped());
+        if let Some(key_file) = &options.key_file {
+            command
+                .arg("-i")
+                .arg(key_file)
+                .arg("-o")
+                .arg("IdentitiesOnly=yes");
+        }
+        if !options.use_agent {
+            command.env_remove("SSH_AUTH_SOCK");
+        }
+        // only needed until ssh has logged in, which it has once the
+        // forwarded port answers, so it goes away when this returns
+        let _askpass = match &options.passphrase {
+            // ssh only reads passphrases from a terminal or an askpass program
+            Some(passphrase) => {
+                let (askpass_dir, askpass) = write_askpass(passphrase)
+                    .map_err(|err| format!("failed to pass on the key passphrase: {}", err))?;
+                // the passphrase is only for the key, never offer it as a password
+                command
+                    .arg("-o")
+                    .arg("PreferredAuthentications=publickey")
+                    .arg("-o")
+                    .arg("PasswordAuthentication=no")
+                    .arg("-o")
+                    .arg("KbdInteractiveAuthentication=no")
+                    .env("SSH_ASKPASS", askpass)
+                    .env("SSH_ASKPASS_REQUIRE", "force");
+                if env::var_os("DISPLAY").is_none() {
+                    command.env("DISPLAY", ":0");
+                }
+                Some(askpass_dir)
+            }
+            None => {
+                command.arg("-o").arg("BatchMode=yes");
+                None
+            }
+        };
+        if options.user.is_empty() {
+            command.arg(&options.host);
+        } else {
+            command.arg(format!("{}@{}", options.user, options.host));
+        }
+
+        let mut child = command
+            .spawn()
+            .map_err(|err| format!("failed to start ssh: {}", err))?;
+        // keep draining stderr for as long as ssh runs, a full pipe would
+        // block it, and hold on to the tail to explain a failed start
+        let stderr = child.stderr.take().map(|output| {
+            thread::spawn(move || {
+                let mut tail = VecDeque::new();
+                // split on bytes, a line that isn't UTF-8 must not stop the draining
+                for line in BufReader::new(output).split(b'\n') {
+                    match line {
+                        Ok(line) => {
+                            if tail.len() == SSH_STDERR_LINES {
+                                tail.pop_front();
+                            }
+                            tail.push_back(String::from_utf8_lossy(&line).into_owned());
+                        }
+                        Err(_) => break,
+                    }
+                }
+                Vec::from(tail).join("\n")
+            })
+        });
+        let deadline = Instant::now() + SSH_STARTUP_TIMEOUT;
+        loop {
+            match child.try_wait() {
+                Ok(Some(status)) => {
+                    let stderr = stderr
+                        .and_then(|reader| reader.join().ok())
+                        .unwrap_or_default();
+                    return Err(format!(
+                        "ssh tunnel to {} failed ({}): {}",
+                        options.host,
+                        status,
+                        stderr.trim()
+                    ));
+                }
+                Ok(None) => {}
+                Err(err) => return Err(format!("failed to wait for ssh: {}", err)),
+            }
+            if TcpStream::connect(("127.0.0.1", local_port)).is_ok() {
+                return Ok(SshTunnel { child, local_port });
+            }
+            if Instant::now() > deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("timed out opening ssh tunnel to {}", options.host));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+}
+
+impl Drop for SshTunnel {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn free_local_port() -> Result<u16, String> {
+    TcpListener::bind(("127.0.0.1", 0))
+        .and_then(|listener| listener.local_addr())
+        .map(|address| address.port())
+        .map_err(|err| format!("failed to find a free local port: {}", err))
+}
+
+/// Writes the passphrase and an askpass script printing it into a private
+/// directory, so it reaches `ssh` without being in anyone's environment.
+/// Returns the directory, to remove both, and the script.
+#[cfg(unix)]
+fn write_askpass(passphrase: &str) -> io::Result<(PrivateDir, PathBuf)> {
+    let dir = PrivateDir::create()?;
+    let secret = dir.path.join("passphrase");
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&secret)?
+        .write_all(format!("{}\n", passphrase).as_bytes())?;
+
+    // single quotes keep the path as is, a quote in it has to be closed,
+    // escaped and reopened
+    let mut script = b"#!/bin/sh\nexec cat '".to_vec();
+    for byte in secret.as_os_str().as_bytes() {
+        match byte {
+            b'\'' => script.extend_from_slice(b"'\\''"),
+            byte => script.push(*byte),
+        }
+    }
+    script.extend_from_slice(b"'\n");
+    let askpass = dir.path.join("askpass");
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o700)
+        .open(&askpass)?
+        .write_all(&script)?;
+    Ok((dir, askpass))
+}
+
+#[cfg(not(unix))]
+fn write_askpass(_passphrase: &str) -> io::Result<(PrivateDir, PathBuf)> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "key passphrases need a unix system, load the key into ssh-agent instead",
+    ))
+}
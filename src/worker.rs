@@ -0,0 +1,570 @@
+//! A background thread owning one `RedisConnection`. Send it `Request`s
+//! through a `WorkerHandle` and get `Reply`s back through a callback; it
+//! keeps the connection alive and reconnects on its own when it drops.
+
+use crate::redislogic::{
+    connect_redis, describe_connection_error, ConnectionConfig, DatabaseInfo, KeyFilter, KeyScan,
+    RedisConnection, RedisValue, RedisViewerError, ScanProgress, ServerInfo, ViewerResult,
+    WriteAction,
+};
+use std::{
+    collections::VecDeque,
+    mem,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_millis(250);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+/// How often a running key scan reports what it found so far.
+const KEY_BATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the UI asks a worker to do.
+#[derive(Debug)]
+pub enum Request {
+    /// Opens the connection, then lists the keys and databases.
+    Connect(ConnectionConfig),
+    /// Lists the keys and databases again.
+    RefreshKeys,
+    /// Lists only the keys `KeyFilter` lets through, now and on every
+    /// refresh after it.
+    FilterKeys(KeyFilter),
+    /// Carries on with the last key listing where it stopped, at the key
+    /// limit or when it was cancelled.
+    LoadMoreKeys,
+    /// Reads one key, answered with `Response::Value`.
+    LoadValue(String),
+    /// Switches database and lists its keys.
+    SelectDatabase(i64),
+    /// Runs a write along with the confirmation the user typed, if any.
+    Write(WriteAction, String),
+    /// Closes the connection and stops the worker.
+    Disconnect,
+}
+
+/// Requests of the same kind supersede each other, only the response to
+/// the latest one of each kind is still of interest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RequestKind {
+    Connect,
+    Keys,
+    Value,
+    Database,
+    Write,
+    Disconnect,
+}
+
+const REQUEST_KINDS: usize = 6;
+
+impl Request {
+    /// Which requests this one supersedes, see `WorkerHandle::is_current`.
+    pub fn kind(&self) -> RequestKind {
+        match self {
+            Request::Connect(_) => RequestKind::Connect,
+            Request::RefreshKeys | Request::FilterKeys(_) | Request::LoadMoreKeys => {
+                RequestKind::Keys
+            }
+            Request::LoadValue(_) => RequestKind::Value,
+            Request::SelectDatabase(_) => RequestKind::Database,
+            Request::Write(..) => RequestKind::Write,
+            Request::Disconnect => RequestKind::Disconnect,
+        }
+    }
+}
+
+/// What a worker reports back.
+#[derive(Debug)]
+pub enum Response {
+    Connected {
+        server_info: ServerInfo,
+        db: i64,
+    },
+    ConnectFailed(String),
+    /// Keys found since the last reply of the same listing. A new listing
+    /// replaces the keys shown before, the last reply of one says how far
+    /// it got.
+    Keys {
+        keys: Vec<String>,
+        progress: ScanProgress,
+        is_new_scan: bool,
+        is_last_batch: bool,
+    },
+    Databases(Vec<DatabaseInfo>),
+    DatabaseSelected(i64),
+    Value {
+        key: String,
+        value: RedisValue,
+        location: Option<String>,
+    },
+    /// A write went through. `value` is the reloaded value after a set.
+    Written {
+        action: WriteAction,
+        value: Option<RedisValue>,
+    },
+    Failed(String),
+    Reconnecting {
+        reason: String,
+        attempt: u32,
+    },
+    Reconnected(ServerInfo),
+    Cancelled,
+}
+
+/// A response tagged with the request it answers. Responses the worker
+/// sends on its own, like reconnect progress, have id 0.
+#[derive(Debug)]
+pub struct Reply {
+    /// What `WorkerHandle::send` returned for the request.
+    pub request_id: u64,
+    /// The kind of the request, `None` for replies nobody asked for.
+    pub kind: Option<RequestKind>,
+    pub response: Response,
+}
+
+/// The UI side of a worker thread. Hands out request ids, remembers the
+/// latest id per kind so superseded responses can be dropped and a
+/// superseded key listing stopped, and cancels long running requests
+/// through a shared counter.
+pub struct WorkerHandle {
+    sender: Sender<(u64, Request)>,
+    next_id: AtomicU64,
+    latest: Arc<[AtomicU64; REQUEST_KINDS]>,
+    cancelled_through: Arc<AtomicU64>,
+}
+
+impl WorkerHandle {
+    /// Starts a worker that calls `respond` from its own thread.
+    pub fn spawn(respond: impl FnMut(Reply) + Send + 'static) -> WorkerHandle {
+        WorkerHandle::spawn_with(connect_redis, respond)
+    }
+
+    /// Like `spawn`, but opens connections with `connect` instead of
+    /// `connect_redis`, e.g. on a `MemoryBackend`.
+    pub fn spawn_with(
+        connect: impl FnMut(ConnectionConfig) -> ViewerResult<RedisConnection> + Send + 'static,
+        respond: impl FnMut(Reply) + Send + 'static,
+    ) -> WorkerHandle {
+        let (sender, receiver) = channel();
+        let latest: Arc<[AtomicU64; REQUEST_KINDS]> = Default::default();
+        let cancelled_through = Arc::new(AtomicU64::new(0));
+        let worker = Worker {
+            receiver,
+            connect,
+            respond,
+            latest: latest.clone(),
+            cancelled_through: cancelled_through.clone(),
+            backlog: VecDeque::new(),
+            connection: None,
+            config: None,
+            filter: KeyFilter::default(),
+            scan: None,
+        };
+        thread::spawn(move || worker.run());
+
+        WorkerHandle {
+            sender,
+            next_id: AtomicU64::new(0),
+            latest,
+            cancelled_through,
+        }
+    }
+
+    /// Queues a request and returns its id. Requests to a worker that has
+    /// already stopped are dropped.
+    pub fn send(&self, request: Request) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        self.latest[request.kind() as usize].store(id, Ordering::SeqCst);
+        let _ = self.sender.send((id, request));
+        id
+    }
+
+    /// Whether nothing of the same kind was requested after `reply`'s request.
+    pub fn is_current(&self, reply: &Reply) -> bool {
+        match reply.kind {
+            Some(kind) if reply.request_id != 0 => {
+                self.latest[kind as usize].load(Ordering::SeqCst) == reply.request_id
+            }
+            _ => true,
+        }
+    }
+
+    /// Abandons every request sent so far, whether queued or running.
+    pub fn cancel_all(&self) {
+        let last_id = self.next_id.load(Ordering::SeqCst);
+        self.cancelled_through.fetch_max(last_id, Ordering::SeqCst);
+    }
+}
+
+struct Worker<C, R> {
+    receiver: Receiver<(u64, Request)>,
+    connect: C,
+    respond: R,
+    latest: Arc<[AtomicU64; REQUEST_KINDS]>,
+    cancelled_through: Arc<AtomicU64>,
+    backlog: VecDeque<(u64, Request)>,
+    connection: Option<RedisConnection>,
+    config: Option<ConnectionConfig>,
+    filter: KeyFilter,
+    /// The last key listing, unless it went through every key.
+    scan: Option<KeyScan>,
+}
+
+impl<C, R> Worker<C, R>
+where
+    C: FnMut(ConnectionConfig) -> ViewerResult<RedisConnection>,
+    R: FnMut(Reply),
+{
+    fn run(mut self) {
+        while let Some((id, request)) = self.next_request() {
+            let kind = request.kind();
+            let result = match request {
+                Request::Disconnect => return,
+                Request::Connect(config) => {
+                    match (self.connect)(config.clone()) {
+                        Ok(mut connection) => {
+                            self.reply(
+                                id,
+                                kind,
+                                Response::Connected {
+                                    server_info: connection.server_info().clone(),
+                                    db: config.info.db,
+                                },
+                            );
+                            self.config = Some(config);
+                            let result = self.refresh(id, kind, &mut connection);
+                            self.connection = Some(connection);
+                            result
+                        }
+                        Err(err) => {
+                            // the tab goes away with this, so does the worker
+                            let message = describe_connection_error(&err);
+                            self.reply(id, kind, Response::ConnectFailed(message));
+                            return;
+                        }
+                    }
+                }
+                request => match self.connection.take() {
+                    Some(mut connection) => {
+                        let result = self.handle(id, kind, &mut connection, request);
+                        self.connection = Some(connection);
+                        result
+                    }
+                    None => {
+                        self.reply(id, kind, Response::Failed("not connected".into()));
+                        Ok(())
+                    }
+                },
+            };
+            if let Err(err) = result {
+                if !self.recover(id, Some(kind), err) {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Blocks until there is something to do, pinging the server whenever
+    /// it has been idle for a while. Returns `None` once the UI is gone.
+    fn next_request(&mut self) -> Option<(u64, Request)> {
+        loop {
+            while let Some((id, request)) = self.backlog.pop_front() {
+                if self.is_cancelled(id) && !matches!(request, Request::Disconnect) {
+                    self.reply(id, request.kind(), Response::Cancelled);
+                    continue;
+                }
+                return Some((id, request));
+            }
+
+            match self.receiver.recv_timeout(KEEPALIVE_INTERVAL) {
+                Ok(envelope) => {
+                    self.backlog.push_back(envelope);
+                    self.backlog.extend(self.receiver.try_iter());
+                    self.drop_superseded();
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    let ping = match &mut self.connection {
+                        Some(connection) => connection.ping(),
+                        None => Ok(()),
+                    };
+                    if let Err(err) = ping {
+                        if !self.recover(0, None, err) {
+                            return None;
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+
+    /// Only the last of several queued value loads is worth running, the
+    /// user has already clicked past the others.
+    fn drop_superseded(&mut self) {
+        let latest = self
+            .backlog
+            .iter()
+            .filter(|(_, request)| request.kind() == RequestKind::Value)
+            .map(|(id, _)| *id)
+            .max();
+        self.backlog
+            .retain(|(id, request)| request.kind() != RequestKind::Value || Some(*id) == latest);
+    }
+
+    fn handle(
+        &mut self,
+        id: u64,
+        kind: RequestKind,
+        connection: &mut RedisConnection,
+        request: Request,
+    ) -> ViewerResult<()> {
+        match request {
+            Request::RefreshKeys => self.refresh(id, kind, connection),
+            Request::FilterKeys(filter) => {
+                self.filter = filter;
+                self.refresh(id, kind, connection)
+            }
+            Request::LoadMoreKeys => match self.scan.take() {
+                Some(scan) => self
+                    .scan_keys(id, kind, connection, scan, false)
+                    .map(|_| ()),
+                None => {
+                    let message = "every key has been listed already".to_string();
+                    self.reply(id, kind, Response::Failed(message));
+                    Ok(())
+                }
+            },
+            Request::SelectDatabase(db) => {
+                connection.select_database(db)?;
+                // reconnecting opens the connection on this db, replaying the SELECT
+                if let Some(config) = &mut self.config {
+                    config.info.db = db;
+                }
+                self.reply(id, kind, Response::DatabaseSelected(db));
+                self.refresh(id, kind, connection)
+            }
+            Request::LoadValue(key) => {
+                let value = connection.get_redis_value(&key)?;
+                let location = connection.key_location(&key);
+                self.reply(
+                    id,
+                    kind,
+                    Response::Value {
+                        key,
+                        value,
+                        location,
+                    },
+                );
+                Ok(())
+            }
+            Request::Write(action, confirmation) => {
+                // read-only and production confirmation are both checked in `perform`
+                connection.perform(&action, &confirmation)?;
+                match &action {
+                    WriteAction::SetValue { key, .. } => {
+                        let value = Some(connection.get_redis_value(key)?);
+                        self.reply(id, kind, Response::Written { action, value });
+                        Ok(())
+                    }
+                    _ => {
+                        self.reply(
+                            id,
+                            kind,
+                            Response::Written {
+                                action,
+                                value: None,
+                            },
+                        );
+                        self.refresh(id, kind, connection)
+                    }
+                }
+            }
+            Request::Connect(_) | Request::Disconnect => Ok(()),
+        }
+    }
+
+    /// Reloads the key list and the databases. The key scan stops early if
+    /// the request gets cancelled.
+    fn refresh(
+        &mut self,
+        id: u64,
+        kind: RequestKind,
+        connection: &mut RedisConnection,
+    ) -> ViewerResult<()> {
+        let scan = connection.start_scan();
+        if !self.scan_keys(id, kind, connection, scan, true)? {
+            return Ok(());
+        }
+        match connection.get_databases() {
+            Ok(databases) => self.reply(id, kind, Response::Databases(databases)),
+            Err(err) if err.is_connection_lost() => return Err(err),
+            Err(err) => self.reply(
+                id,
+                kind,
+                Response::Failed(format!("failed to list databases: {}", err)),
+            ),
+        }
+        Ok(())
+    }
+
+    /// Streams the keys of `scan` in replies at most `KEY_BATCH_INTERVAL`
+    /// apart, until every key is listed, the key limit is reached or the
+    /// request is cancelled. Returns `false` if it was cancelled, or
+    /// superseded by a newer key listing, which is left to start over.
+    /// Otherwise whatever is left of the scan is kept for `LoadMoreKeys`.
+    fn scan_keys(
+        &mut self,
+        id: u64,
+        kind: RequestKind,
+        connection: &mut RedisConnection,
+        mut scan: KeyScan,
+        mut is_new_scan: bool,
+    ) -> ViewerResult<bool> {
+        let filter = self.filter.clone();
+        let key_limit = self.config.as_ref().and_then(|config| config.key_limit);
+        let stop_at = key_limit.map(|limit| scan.progress().keys_found + limit);
+        let mut keys = Vec::new();
+        let mut last_reply = Instant::now();
+        loop {
+            if self.is_superseded(id) {
+                self.scan = None;
+                return Ok(false);
+            }
+            let is_cancelled = self.is_cancelled(id);
+            let is_limit_reached =
+                stop_at.map_or(false, |stop_at| scan.progress().keys_found >= stop_at);
+            let is_last_batch = is_cancelled || is_limit_reached || scan.is_done();
+            if is_last_batch || last_reply.elapsed() >= KEY_BATCH_INTERVAL {
+                let response = Response::Keys {
+                    keys: mem::take(&mut keys),
+                    progress: scan.progress(),
+                    is_new_scan,
+                    is_last_batch,
+                };
+                self.reply(id, kind, response);
+                is_new_scan = false;
+                last_reply = Instant::now();
+            }
+            if is_last_batch {
+                self.scan = Some(scan).filter(|scan| !scan.is_done());
+                if is_cancelled {
+                    self.reply(id, kind, Response::Cancelled);
+                }
+                return Ok(!is_cancelled);
+            }
+            match connection.scan_keys(&mut scan, &filter) {
+                Ok(batch) => keys.extend(batch),
+                Err(err) => {
+                    // a failed step leaves the scan where it was
+                    self.scan = Some(scan);
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// Reports a failed command, and reconnects if the connection is gone.
+    /// Returns `false` once the worker should stop.
+    fn recover(&mut self, id: u64, kind: Option<RequestKind>, mut err: RedisViewerError) -> bool {
+        loop {
+            let failed_over = self
+                .config
+                .as_ref()
+                .map_or(false, |config| config.is_failover_error(&err));
+            if !err.is_connection_lost() && !failed_over {
+                self.respond_to(id, kind, Response::Failed(err.to_string()));
+                return true;
+            }
+
+            // the request is not retried, so say it failed rather than leave
+            // a write unanswered; the refresh below lists the keys again
+            if kind != Some(RequestKind::Keys) {
+                self.respond_to(id, kind, Response::Failed(err.to_string()));
+            }
+            self.connection = None;
+            let mut connection = match self.reconnect(&err) {
+                Some(connection) => connection,
+                None => return false,
+            };
+            let server_info = connection.server_info().clone();
+            self.unsolicited(Response::Reconnected(server_info));
+            // the server may have changed while we were away, reload the tab
+            match self.refresh(0, RequestKind::Keys, &mut connection) {
+                Ok(()) => {
+                    self.connection = Some(connection);
+                    return true;
+                }
+                Err(next_err) => err = next_err,
+            }
+        }
+    }
+
+    /// Retries with exponential backoff until the server is back, or
+    /// returns `None` once the UI asks to disconnect or goes away.
+    /// Requests that arrive in the meantime are kept for afterwards.
+    fn reconnect(&mut self, cause: &RedisViewerError) -> Option<RedisConnection> {
+        let config = self.config.clone()?;
+        let mut delay = INITIAL_RECONNECT_DELAY;
+        let mut attempt = 0;
+        let mut reason = describe_connection_error(cause);
+        loop {
+            attempt += 1;
+            self.unsolicited(Response::Reconnecting {
+                reason: reason.clone(),
+                attempt,
+            });
+
+            let deadline = Instant::now() + delay;
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining == Duration::ZERO {
+                    break;
+                }
+                match self.receiver.recv_timeout(remaining) {
+                    Ok((_, Request::Disconnect)) | Err(RecvTimeoutError::Disconnected) => {
+                        return None
+                    }
+                    Ok(envelope) => self.backlog.push_back(envelope),
+                    Err(RecvTimeoutError::Timeout) => {}
+                }
+            }
+
+            match (self.connect)(config.clone()) {
+                Ok(connection) => return Some(connection),
+                Err(err) => reason = describe_connection_error(&err),
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    fn is_cancelled(&self, id: u64) -> bool {
+        id != 0 && self.cancelled_through.load(Ordering::SeqCst) >= id
+    }
+
+    /// Whether a key listing was asked for after request `id`, so whatever
+    /// `id` is still listing won't be shown.
+    fn is_superseded(&self, id: u64) -> bool {
+        id != 0 && self.latest[RequestKind::Keys as usize].load(Ordering::SeqCst) > id
+    }
+
+    fn reply(&mut self, request_id: u64, kind: RequestKind, response: Response) {
+        self.respond_to(request_id, Some(kind), response);
+    }
+
+    fn unsolicited(&mut self, response: Response) {
+        self.respond_to(0, None, response);
+    }
+
+    fn respond_to(&mut self, request_id: u64, kind: Option<RequestKind>, response: Response) {
+        (self.respond)(Reply {
+            request_id,
+            kind,
+            response,
+        });
+    }
+}
@@ -0,0 +1,371 @@
+use druid_redis_viewer::memory::MemoryBackend;
+use druid_redis_viewer::redislogic::{
+    build_connection_info, ConnectionConfig, KeyFilter, RedisValue, ScanProgress, WriteAction,
+};
+use druid_redis_viewer::worker::{Reply, Request, Response, WorkerHandle};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::Duration;
+
+fn config() -> ConnectionConfig {
+    ConnectionConfig::new(build_connection_info("localhost", "6379", "0", "", "").unwrap())
+}
+
+fn spawn(backend: &MemoryBackend) -> (WorkerHandle, Receiver<Reply>) {
+    let (sender, replies) = channel();
+    let backend = backend.clone();
+    let worker = WorkerHandle::spawn_with(
+        move |config| backend.connect(&config),
+        move |reply| {
+            let _ = sender.send(reply);
+        },
+    );
+    (worker, replies)
+}
+
+fn next(replies: &Receiver<Reply>) -> Response {
+    replies
+        .recv_timeout(Duration::from_secs(10))
+        .expect("the worker stopped replying")
+        .response
+}
+
+/// Connects and checks the replies every connect ends with.
+fn connect(worker: &WorkerHandle, replies: &Receiver<Reply>, config: ConnectionConfig) {
+    worker.send(Request::Connect(config));
+    assert!(matches!(next(replies), Response::Connected { db: 0, .. }));
+    next_keys(replies);
+    assert!(matches!(next(replies), Response::Databases(_)));
+}
+
+/// Collects the keys of one listing, which may take several replies.
+fn next_keys(replies: &Receiver<Reply>) -> (Vec<String>, ScanProgress) {
+    let mut all_keys = Vec::new();
+    loop {
+        match next(replies) {
+            Response::Keys {
+                keys,
+                progress,
+                is_last_batch,
+                ..
+            } => {
+                all_keys.extend(keys);
+                if is_last_batch {
+                    return (all_keys, progress);
+                }
+            }
+            other => panic!("expected Keys, got {:?}", other),
+        }
+    }
+}
+
+fn seeded_backend() -> MemoryBackend {
+    let backend = MemoryBackend::new();
+    backend.insert(0, "greeting", RedisValue::String("hello".into()));
+    backend.insert(0, "user:1", RedisValue::String("ada".into()));
+    backend
+}
+
+#[test]
+fn connect_lists_keys_and_databases() {
+    let backend = seeded_backend();
+    let (worker, replies) = spawn(&backend);
+
+    worker.send(Request::Connect(config()));
+    match next(&replies) {
+        Response::Connected { server_info, db } => {
+            assert_eq!(db, 0);
+            assert_eq!(server_info.version, "memory");
+        }
+        other => panic!("expected Connected, got {:?}", other),
+    }
+    let (keys, _) = next_keys(&replies);
+    assert_eq!(keys, vec!["greeting", "user:1"]);
+    match next(&replies) {
+        Response::Databases(databases) => assert_eq!(databases[0].keys, 2),
+        other => panic!("expected Databases, got {:?}", other),
+    }
+}
+
+#[test]
+fn connect_failure_is_reported() {
+    let backend = MemoryBackend::new();
+    backend.set_offline(true);
+    let (worker, replies) = spawn(&backend);
+
+    worker.send(Request::Connect(config()));
+    match next(&replies) {
+        Response::ConnectFailed(message) => assert!(message.contains("refused"), "{}", message),
+        other => panic!("expected ConnectFailed, got {:?}", other),
+    }
+}
+
+#[test]
+fn load_value_replies_with_the_value() {
+    let backend = seeded_backend();
+    let (worker, replies) = spawn(&backend);
+    connect(&worker, &replies, config());
+
+    worker.send(Request::LoadValue("greeting".into()));
+    match next(&replies) {
+        Response::Value {
+            key,
+            value,
+            location,
+        } => {
+            assert_eq!(key, "greeting");
+            assert_eq!(value, RedisValue::String("hello".into()));
+            assert_eq!(location, None);
+        }
+        other => panic!("expected Value, got {:?}", other),
+    }
+}
+
+#[test]
+fn set_value_replies_with_the_stored_value() {
+    let backend = seeded_backend();
+    let (worker, replies) = spawn(&backend);
+    connect(&worker, &replies, config());
+
+    let action = WriteAction::SetValue {
+        key: "greeting".into(),
+        value: "bonjour".into(),
+    };
+    worker.send(Request::Write(action.clone(), String::new()));
+    match next(&replies) {
+        Response::Written {
+            action: written,
+            value,
+        } => {
+            assert_eq!(written, action);
+            assert_eq!(value, Some(RedisValue::String("bonjour".into())));
+        }
+        other => panic!("expected Written, got {:?}", other),
+    }
+    assert_eq!(
+        backend.get(0, "greeting"),
+        Some(RedisValue::String("bonjour".into()))
+    );
+}
+
+#[test]
+fn delete_refreshes_the_keys() {
+    let backend = seeded_backend();
+    let (worker, replies) = spawn(&backend);
+    connect(&worker, &replies, config());
+
+    let action = WriteAction::DeleteKey {
+        key: "greeting".into(),
+    };
+    worker.send(Request::Write(action, String::new()));
+    assert!(matches!(
+        next(&replies),
+        Response::Written { value: None, .. }
+    ));
+    let (keys, _) = next_keys(&replies);
+    assert_eq!(keys, vec!["user:1"]);
+}
+
+#[test]
+fn read_only_writes_fail_without_changing_anything() {
+    let backend = seeded_backend();
+    let (worker, replies) = spawn(&backend);
+    connect(&worker, &replies, config().in_read_only_mode());
+
+    let action = WriteAction::DeleteKey {
+        key: "greeting".into(),
+    };
+    worker.send(Request::Write(action, String::new()));
+    match next(&replies) {
+        Response::Failed(message) => assert!(message.contains("read-only"), "{}", message),
+        other => panic!("expected Failed, got {:?}", other),
+    }
+    assert!(backend.get(0, "greeting").is_some());
+}
+
+#[test]
+fn select_database_switches_and_refreshes() {
+    let backend = seeded_backend();
+    backend.insert(3, "elsewhere", RedisValue::String("value".into()));
+    let (worker, replies) = spawn(&backend);
+    connect(&worker, &replies, config());
+
+    worker.send(Request::SelectDatabase(3));
+    assert!(matches!(next(&replies), Response::DatabaseSelected(3)));
+    let (keys, _) = next_keys(&replies);
+    assert_eq!(keys, vec!["elsewhere"]);
+}
+
+#[test]
+fn only_the_latest_request_of_a_kind_is_current() {
+    let backend = seeded_backend();
+    let (worker, replies) = spawn(&backend);
+    connect(&worker, &replies, config());
+
+    worker.send(Request::LoadValue("greeting".into()));
+    worker.send(Request::LoadValue("user:1".into()));
+    loop {
+        let reply = replies.recv_timeout(Duration::from_secs(10)).unwrap();
+        match &reply.response {
+            Response::Value { key, .. } if key == "user:1" => {
+                assert!(worker.is_current(&reply));
+                break;
+            }
+            Response::Value { .. } => assert!(!worker.is_current(&reply)),
+            other => panic!("expected Value, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn filter_keys_applies_to_later_refreshes() {
+    let backend = seeded_backend();
+    let (worker, replies) = spawn(&backend);
+    connect(&worker, &replies, config());
+
+    let filter = KeyFilter::parse("user:*", "", "").unwrap();
+    worker.send(Request::FilterKeys(filter));
+    let (keys, _) = next_keys(&replies);
+    assert_eq!(keys, vec!["user:1".to_string()]);
+    assert!(matches!(next(&replies), Response::Databases(_)));
+
+    backend.insert(0, "user:2", RedisValue::String("grace".into()));
+    worker.send(Request::RefreshKeys);
+    let (keys, _) = next_keys(&replies);
+    assert_eq!(keys, vec!["user:1".to_string(), "user:2".into()]);
+}
+
+#[test]
+fn a_newer_key_listing_stops_the_one_before() {
+    let backend = seeded_backend();
+    let (worker, replies) = spawn(&backend);
+    connect(&worker, &replies, config());
+
+    // both listings queue up while the worker waits for the server
+    backend.set_offline(true);
+    worker.send(Request::RefreshKeys);
+    assert!(matches!(
+        next(&replies),
+        Response::Reconnecting { attempt: 1, .. }
+    ));
+    let users = KeyFilter::parse("user:*", "", "").unwrap();
+    worker.send(Request::FilterKeys(users));
+    let greeting = KeyFilter::parse("greeting", "", "").unwrap();
+    let latest = worker.send(Request::FilterKeys(greeting));
+    backend.set_offline(false);
+
+    loop {
+        match next(&replies) {
+            Response::Reconnecting { .. } => continue,
+            Response::Reconnected(_) => break,
+            other => panic!("expected Reconnected, got {:?}", other),
+        }
+    }
+    next_keys(&replies);
+    assert!(matches!(next(&replies), Response::Databases(_)));
+
+    let reply = replies.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(reply.request_id, latest);
+    match reply.response {
+        Response::Keys { keys, .. } => assert_eq!(keys, vec!["greeting".to_string()]),
+        other => panic!("expected Keys, got {:?}", other),
+    }
+}
+
+#[test]
+fn reconnects_after_the_connection_drops() {
+    let backend = seeded_backend();
+    let (worker, replies) = spawn(&backend);
+    connect(&worker, &replies, config());
+
+    backend.set_offline(true);
+    worker.send(Request::RefreshKeys);
+    assert!(matches!(
+        next(&replies),
+        Response::Reconnecting { attempt: 1, .. }
+    ));
+    backend.set_offline(false);
+
+    loop {
+        match next(&replies) {
+            Response::Reconnecting { .. } => continue,
+            Response::Reconnected(_) => break,
+            other => panic!("expected Reconnected, got {:?}", other),
+        }
+    }
+    next_keys(&replies);
+}
+
+#[test]
+fn a_write_lost_with_the_connection_is_reported_as_failed() {
+    let backend = seeded_backend();
+    let (worker, replies) = spawn(&backend);
+    connect(&worker, &replies, config());
+
+    backend.set_offline(true);
+    let action = WriteAction::SetValue {
+        key: "greeting".into(),
+        value: "bonjour".into(),
+    };
+    worker.send(Request::Write(action, String::new()));
+    assert!(matches!(next(&replies), Response::Failed(_)));
+    assert!(matches!(
+        next(&replies),
+        Response::Reconnecting { attempt: 1, .. }
+    ));
+    backend.set_offline(false);
+
+    loop {
+        match next(&replies) {
+            Response::Reconnecting { .. } => continue,
+            Response::Reconnected(_) => break,
+            other => panic!("expected Reconnected, got {:?}", other),
+        }
+    }
+    next_keys(&replies);
+    assert_eq!(
+        backend.get(0, "greeting"),
+        Some(RedisValue::String("hello".into()))
+    );
+}
+
+#[test]
+fn listing_pauses_at_the_key_limit_and_loads_more() {
+    let backend = MemoryBackend::new();
+    for index in 0..25 {
+        backend.insert(
+            0,
+            &format!("key:{:02}", index),
+            RedisValue::String("v".into()),
+        );
+    }
+    let (worker, replies) = spawn(&backend);
+    worker.send(Request::Connect(config().with_key_limit(Some(10))));
+    assert!(matches!(next(&replies), Response::Connected { .. }));
+    worker.send(Request::FilterKeys(KeyFilter::parse("*", "", "5").unwrap()));
+
+    // the connect listing, with the default filter
+    let (keys, progress) = next_keys(&replies);
+    assert_eq!(keys.len(), 25);
+    assert!(progress.is_done);
+    assert!(matches!(next(&replies), Response::Databases(_)));
+
+    let (keys, progress) = next_keys(&replies);
+    assert_eq!(keys.len(), 10);
+    assert_eq!(progress.batches, 2);
+    assert!(!progress.is_done);
+    assert!(matches!(next(&replies), Response::Databases(_)));
+
+    worker.send(Request::LoadMoreKeys);
+    let (more, progress) = next_keys(&replies);
+    assert_eq!(more.len(), 10);
+    assert_eq!(progress.keys_found, 20);
+    assert!(!more.iter().any(|key| keys.contains(key)));
+
+    worker.send(Request::LoadMoreKeys);
+    let (rest, progress) = next_keys(&replies);
+    assert_eq!(rest.len(), 5);
+    assert!(progress.is_done);
+
+    worker.send(Request::LoadMoreKeys);
+    assert!(matches!(next(&replies), Response::Failed(_)));
+}